@@ -33,10 +33,12 @@ pub enum ApiType {
 }
 
 impl ApiType {
-    /*
+    // The borrowed counterpart of `rust_owned_name`, used for function parameters so that
+    // calling a generated function doesn't force an allocation/clone just to hand the argument
+    // to `IntoValue` (see the reference `IntoValue` impls in `lib.rs`).
     pub fn rust_borrowed_name(&self) -> String {
         use itertools::Itertools;
-        use self::ApiType::*;
+        use ApiType::*;
 
         match *self {
             Nil => "()".into(),
@@ -45,19 +47,23 @@ impl ApiType {
             Float => "f64".into(),
             String => "&str".into(),
             Array => "&[Value]".into(),
-            ArrayOf(ref ty) => format!("&[{}]", ty.rust_borrowed_name()),
+            // There's only a reference `IntoValue` impl for `&[Value]` (see `lib.rs`), so only
+            // borrow when the element type is `Object`; anything else falls back to the owned
+            // `Vec<...>` form, which is covered by the blanket `impl<T: IntoValue> IntoValue for
+            // Vec<T>`.
+            ArrayOf(ref ty) if **ty == Object => "&[Value]".into(),
+            ArrayOf(ref ty) => ArrayOf(ty.clone()).rust_owned_name(),
             ArrayOfLength(ref ty, num) => {
                 let ty_str = ty.rust_borrowed_name();
                 format!("({})", std::iter::repeat(ty_str).take(num as usize).format(", "))
             },
             Dictionary => "&[(Value, Value)]".into(),
-            Buffer => "Buffer".into(),
-            Window => "Window".into(),
-            Tabpage => "Tabpage".into(),
+            Buffer => "Buffer<'client>".into(),
+            Window => "Window<'client>".into(),
+            Tabpage => "Tabpage<'client>".into(),
             Object => "&Value".into(),
         }
     }
-    */
 
     pub fn rust_owned_name(&self) -> String {
         use itertools::Itertools;
@@ -189,9 +195,10 @@ impl<'a> ApiFunction<'a> {
         let macro_name = if self.method { "nvim_api_method" } else { "nvim_api_function" };
         let param_strings = self.parameters.iter()
             .skip(num_to_skip)
-            // Note that we're using owned names, because rmpv prefers owned names for converting
-            // into Values.
-            .map(|p| format!("{}: {}", p.name, p.ty.rust_owned_name()))
+            // Parameters are taken by reference where possible (e.g. `&str` instead of
+            // `String`), so that callers don't have to allocate an owned copy just to pass an
+            // argument; see the reference `IntoValue` impls in `lib.rs`.
+            .map(|p| format!("{}: {}", p.name, p.ty.rust_borrowed_name()))
             .collect::<Vec<_>>();
 
         format!(r#"{macro_name}!({fn_name}, "{nvim_fn_name}", {params}; {ret_ty});"#,
@@ -212,9 +219,60 @@ pub struct ApiUiEvent<'a> {
     pub since: i64,
 }
 
+impl<'a> ApiUiEvent<'a> {
+    // Converts e.g. "mode_info_set" into "ModeInfoSet".
+    fn rust_variant_name(&self) -> String {
+        self.name.split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(c) => c.to_uppercase().collect::<String>() + chars.as_str(),
+                    None => String::new(),
+                }
+            })
+            .collect()
+    }
+
+    fn rust_decode_fn_name(&self) -> String {
+        format!("decode_{}", self.name)
+    }
+
+    pub fn variant_decl(&self) -> String {
+        let param_strings = self.parameters.iter()
+            .map(|p| p.ty.rust_owned_name())
+            .collect::<Vec<_>>();
+
+        format!("{variant}({params})",
+            variant = self.rust_variant_name(),
+            params = param_strings.join(", "),
+        )
+    }
+
+    pub fn macro_call(&self) -> String {
+        let param_strings = self.parameters.iter()
+            .map(|p| format!("{}: {}", p.name, p.ty.rust_owned_name()))
+            .collect::<Vec<_>>();
+
+        format!(r#"nvim_api_event!({fn_name}, {variant}, {params});"#,
+            fn_name = self.rust_decode_fn_name(),
+            variant = self.rust_variant_name(),
+            params = param_strings.join(", "),
+        )
+    }
+
+    pub fn dispatch_arm(&self) -> String {
+        format!(r#""{nvim_name}" => Some({fn_name}(args, client)),"#,
+            nvim_name = self.name,
+            fn_name = self.rust_decode_fn_name(),
+        )
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
 pub struct ApiTypeDecl<'a> {
     pub prefix: &'a str,
+    // The msgpack ext type code that neovim uses to encode handles of this type on the wire.
+    pub id: i64,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
@@ -223,7 +281,7 @@ pub struct Api<'a> {
     #[serde(borrow)]
     pub functions: Vec<ApiFunction<'a>>,
     #[serde(borrow)]
-    ui_events: Vec<ApiUiEvent<'a>>,
+    pub ui_events: Vec<ApiUiEvent<'a>>,
     #[serde(borrow)]
     pub types: HashMap<&'a str, ApiTypeDecl<'a>>,
 }
@@ -49,8 +49,31 @@ fn do_main() -> Result<(), Error> {
     }
     writeln!(out, "}}")?;
 
+    // The `UiEvent` enum carries one variant per notification that `nvim_ui_attach` can cause
+    // neovim to send, decoded from the raw `Value` arguments via the generated decoder functions
+    // below.
+    writeln!(out, "#[derive(Debug)]")?;
+    writeln!(out, "pub enum UiEvent<'client> {{")?;
+    for e in &api.ui_events {
+        writeln!(out, "\t{},", e.variant_decl())?;
+    }
+    writeln!(out, "}}")?;
+
+    for e in &api.ui_events {
+        writeln!(out, "{}", e.macro_call())?;
+    }
+
+    writeln!(out, "pub(crate) fn decode_ui_event<'client>(name: &str, args: Vec<Value>, client: &'client RpcClient) -> Option<Result<UiEvent<'client>, Error>> {{")?;
+    writeln!(out, "\tmatch name {{")?;
+    for e in &api.ui_events {
+        writeln!(out, "\t\t{}", e.dispatch_arm())?;
+    }
+    writeln!(out, "\t\t_ => None,")?;
+    writeln!(out, "\t}}")?;
+    writeln!(out, "}}")?;
+
     for (name, ref decl) in &api.types {
-        writeln!(out, "nvim_type!({});", name)?;
+        writeln!(out, "nvim_type!({}, {});", name, decl.id)?;
         writeln!(out, "impl<'client> {}<'client> {{", name)?;
 
         for f in &api.functions {
@@ -33,7 +33,7 @@ fn do_main() -> Result<(), Error> {
     let handle = core.handle();
     let client = NvimClient::from_unix_socket(matches.value_of("servername").unwrap(), &handle)?;
 
-    let client_task = client.eval(matches.value_of("eval").unwrap().to_owned())
+    let client_task = client.eval(matches.value_of("eval").unwrap())
         .and_then(|response| {
             println!("Got response: {:?}", response);
             Ok(())
@@ -8,25 +8,79 @@ extern crate rmpv;
 extern crate serde;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_process;
 extern crate tokio_uds;
 
 #[macro_use] extern crate failure_derive;
 
+use futures::{stream, Future, Poll, Stream};
 use rmp_rpc::{Client as RpcClient};
 use rmpv::Value;
-use std::io;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use tokio_core::net::TcpStream;
 use tokio_core::reactor;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
 use tokio_uds::UnixStream;
 
 #[derive(Debug, Fail)]
 pub enum Error {
     IoError(io::Error),
+    NvimError(NvimError),
+    // Kept around as a fallback for the (unexpected) case where neovim's error response isn't
+    // the usual `[error_type_id, message]` array.
     NvimReturnedError(Value),
     UnexpectedReturnType(Value),
     ConnectionClosed,
 }
 
+/// A structured version of the errors that neovim's RPC layer returns, decoded from the
+/// `[error_type_id, message]` array it always sends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NvimError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Exception,
+    Validation,
+    Other(i64),
+}
+
+impl ErrorKind {
+    fn from_id(id: i64) -> ErrorKind {
+        match id {
+            0 => ErrorKind::Exception,
+            1 => ErrorKind::Validation,
+            other => ErrorKind::Other(other),
+        }
+    }
+}
+
+// Neovim always returns RPC errors as a two-element `[error_type_id, message]` array; this
+// decodes that shape, falling back to `Error::NvimReturnedError` when it doesn't match.
+fn decode_nvim_error(v: Value) -> Error {
+    match v {
+        Value::Array(ref a) if a.len() == 2 => {
+            match (a[0].as_i64(), a[1].as_str()) {
+                (Some(id), Some(message)) => Error::NvimError(NvimError {
+                    kind: ErrorKind::from_id(id),
+                    message: message.to_owned(),
+                }),
+                _ => Error::NvimReturnedError(v.clone()),
+            }
+        }
+        _ => Error::NvimReturnedError(v),
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         std::fmt::Debug::fmt(self, f)
@@ -51,6 +105,9 @@ impl From<Map> for Value {
 
 pub struct NvimClient {
     client: RpcClient,
+    // Keeps a neovim process spawned by `spawn_embedded` alive (and kills it on drop); `None`
+    // for clients connected to an already-running neovim.
+    _child: Option<Child>,
 }
 
 impl NvimClient {
@@ -58,8 +115,136 @@ impl NvimClient {
         let stream = UnixStream::connect(path, handle)?;
         Ok(NvimClient {
             client: RpcClient::new(stream, handle),
+            _child: None,
         })
     }
+
+    /// Connects to a neovim instance listening on a TCP socket (`nvim --listen host:port`).
+    pub fn from_tcp(addr: &SocketAddr, handle: &reactor::Handle) -> impl Future<Item = NvimClient, Error = io::Error> {
+        let handle = handle.clone();
+        TcpStream::connect(addr, &handle)
+            .map(move |stream| NvimClient {
+                client: RpcClient::new(stream, &handle),
+                _child: None,
+            })
+    }
+
+    /// Spawns `nvim_path --embed --headless <args>` and connects to it over its stdin/stdout
+    /// pipes, as embedders that don't have (or don't want) a pre-existing server do. The child
+    /// is killed when the returned `NvimClient` is dropped.
+    pub fn spawn_embedded<P, I, S>(nvim_path: P, args: I, handle: &reactor::Handle) -> Result<NvimClient, io::Error>
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut child = Command::new(nvim_path)
+            .arg("--embed")
+            .arg("--headless")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn_async(handle)?;
+
+        let stdin = child.stdin().take().expect("spawned with a piped stdin");
+        let stdout = child.stdout().take().expect("spawned with a piped stdout");
+
+        Ok(NvimClient {
+            client: RpcClient::new(EmbeddedIo { stdin, stdout }, handle),
+            _child: Some(child),
+        })
+    }
+}
+
+impl Drop for NvimClient {
+    fn drop(&mut self) {
+        // Neither `std::process::Child` nor `tokio_process::Child` kill the child on drop, so
+        // without this an `nvim --embed` spawned by `spawn_embedded` would outlive us.
+        if let Some(ref mut child) = self._child {
+            let _ = child.kill();
+        }
+    }
+}
+
+// `RpcClient::new` wants a single type that's both `AsyncRead` and `AsyncWrite`, but a child
+// process gives us its stdin and stdout as two separate handles; this glues them together.
+struct EmbeddedIo {
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl io::Read for EmbeddedIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl io::Write for EmbeddedIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl AsyncRead for EmbeddedIo {}
+
+impl AsyncWrite for EmbeddedIo {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stdin.shutdown()
+    }
+}
+
+/// Implemented by programs that want to act as a neovim remote plugin: instead of only calling
+/// into neovim, a `Handler` answers the `rpcrequest`/`rpcnotify` calls that neovim makes back to
+/// us (see `NvimClient::serve`).
+pub trait Handler {
+    type RequestFuture: Future<Item = Value, Error = Value>;
+
+    fn handle_request(&self, method: &str, args: Vec<Value>) -> Self::RequestFuture;
+    fn handle_notify(&self, method: &str, args: Vec<Value>);
+}
+
+// Adapts our `Handler` trait to whatever shape `rmp_rpc` wants on the server side of a
+// connection.
+struct HandlerService<H> {
+    handler: Rc<H>,
+}
+
+impl<H: Handler> rmp_rpc::Service for HandlerService<H> {
+    type T = Value;
+    type E = Value;
+    type Error = ();
+    type RequestFuture = H::RequestFuture;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
+        self.handler.handle_request(method, params.to_vec())
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &[Value]) {
+        self.handler.handle_notify(method, params.to_vec())
+    }
+}
+
+impl NvimClient {
+    /// Runs `handler` as a neovim remote plugin over `stream`, answering neovim's
+    /// `rpcrequest`/`rpcnotify` calls for as long as the returned future is driven. The returned
+    /// `NvimClient` shares the same connection, so the handler can call back into neovim (e.g.
+    /// via a `RefCell` it holds) while it is being served.
+    pub fn serve<H, S>(handler: H, stream: S, handle: &reactor::Handle)
+        -> (NvimClient, impl Future<Item = (), Error = io::Error>)
+    where
+        H: Handler + 'static,
+        S: AsyncRead + AsyncWrite + 'static,
+    {
+        let (client, server) = rmp_rpc::Endpoint::new(stream, handle);
+        let service = HandlerService { handler: Rc::new(handler) };
+        let serve_future = server.serve(service);
+        (NvimClient { client, _child: None }, serve_future)
+    }
 }
 
 trait FromValue<'client> {
@@ -154,36 +339,85 @@ impl<S: IntoValue, T: IntoValue> IntoValue for (S, T) {
     }
 }
 
+// Reference counterparts of the impls above, used by generated functions that take their
+// parameters by reference (see `ApiType::rust_borrowed_name`) so that callers don't have to make
+// an owned copy of an argument first; these impls copy the borrowed data into the request's
+// `Value` arguments.
+impl<'a> IntoValue for &'a str {
+    fn into_value(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+impl<'a> IntoValue for &'a Value {
+    fn into_value(self) -> Value {
+        self.clone()
+    }
+}
+
+impl<'a> IntoValue for &'a [Value] {
+    fn into_value(self) -> Value {
+        Value::Array(self.to_vec())
+    }
+}
+
+impl<'a> IntoValue for &'a [(Value, Value)] {
+    fn into_value(self) -> Value {
+        Value::Map(self.to_vec())
+    }
+}
+
 fn convert_ret<'client, Ret>(nvim_ret: Result<Value, Value>, client: &'client RpcClient)
 -> Result<Ret, Error>
 where Ret: FromValue<'client>
 {
     match nvim_ret {
         Ok(x) => Ret::from_value(x, client),
-        Err(x) => Err(Error::NvimReturnedError(x)),
+        Err(x) => Err(decode_nvim_error(x)),
     }
 }
 
-// A macro for generating a wrapper for a neovim api type.
+// A macro for generating a wrapper for a neovim api type. Neovim transmits these handles as
+// msgpack ext values: `$ext_id` is the type's numeric ext code (from the `types` map of `nvim
+// --api-info`), and the ext payload is itself a msgpack-encoded integer.
 macro_rules! nvim_type {
-    ($ty_name: ident) => {
+    ($ty_name: ident, $ext_id: expr) => {
         pub struct $ty_name<'client> {
             client: &'client RpcClient,
-            data: Value,
+            data: i64,
+        }
+
+        // `RpcClient` isn't `Debug`, so derive isn't an option; print just the handle id, which
+        // is all that's ever interesting about one of these.
+        impl<'client> std::fmt::Debug for $ty_name<'client> {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.debug_tuple(stringify!($ty_name)).field(&self.data).finish()
+            }
+        }
+
+        impl<'client> $ty_name<'client> {
+            // The `Value` used to send this handle back to neovim as an argument.
+            fn handle_value(&self) -> Value {
+                let payload = rmp_serde::to_vec(&self.data).expect("encoding an i64 never fails");
+                Value::Ext($ext_id, payload)
+            }
         }
 
         impl<'client> FromValue<'client> for $ty_name<'client> {
-            fn from_value(data: Value, client: &'client RpcClient) -> Result<$ty_name<'client>, Error> {
-                Ok($ty_name {
-                    client,
-                    data,
-                })
+            fn from_value(v: Value, client: &'client RpcClient) -> Result<$ty_name<'client>, Error> {
+                match v {
+                    Value::Ext(id, ref payload) if id == $ext_id => {
+                        let data = rmp_serde::from_slice(payload).map_err(|_e| Error::UnexpectedReturnType(v.clone()))?;
+                        Ok($ty_name { client, data })
+                    }
+                    _ => Err(Error::UnexpectedReturnType(v)),
+                }
             }
         }
 
         impl<'client> IntoValue for $ty_name<'client> {
             fn into_value(self) -> Value {
-                self.data
+                self.handle_value()
             }
         }
     }
@@ -193,7 +427,7 @@ macro_rules! nvim_type {
 macro_rules! nvim_api_method {
     ($fn_name:ident, $nvim_fn_name:expr, $( $arg_name:ident : $arg_ty:ty ),*; $ret_ty:ty) => {
         pub fn $fn_name(&'client self, $( $arg_name : $arg_ty ),*) -> impl Future<Item = $ret_ty, Error = Error> + 'client {
-            self.client.request($nvim_fn_name, &[ self.data.clone(), $( $arg_name.into_value() ),* ])
+            self.client.request($nvim_fn_name, &[ self.handle_value(), $( $arg_name.into_value() ),* ])
                 .map_err(|_e| Error::ConnectionClosed)
                 .and_then(move |v| convert_ret(v, self.client))
         }
@@ -225,5 +459,171 @@ macro_rules! nvim_api_function {
     }
 }
 
+// A macro for generating a decoder for one variant of `UiEvent`, analogous to
+// `nvim_api_function!`.
+macro_rules! nvim_api_event {
+    ($fn_name:ident, $variant:ident, $( $arg_name:ident : $arg_ty:ty ),*) => {
+        #[allow(non_snake_case)]
+        fn $fn_name<'client>(args: Vec<Value>, client: &'client RpcClient) -> Result<UiEvent<'client>, Error> {
+            let mut args = args.into_iter();
+            $(
+                let $arg_name = <$arg_ty as FromValue>::from_value(
+                    args.next().ok_or_else(|| Error::UnexpectedReturnType(Value::Nil))?,
+                    client,
+                )?;
+            )*
+            Ok(UiEvent::$variant($( $arg_name ),*))
+        }
+    }
+}
+
 mod api_autogen;
+use api_autogen::{decode_ui_event, UiEvent};
+
+// A single `redraw` notification batches many `[event_name, args...]` groups together, and each
+// group can itself contain more than one occurrence of that event (e.g. several `cursor_goto`
+// calls). This decodes every occurrence in one such group, skipping (rather than erroring on)
+// event names we don't recognize.
+fn decode_redraw_group<'client>(group: Value, client: &'client RpcClient) -> Vec<UiEvent<'client>> {
+    let mut events = Vec::new();
+    let mut parts = match group {
+        Value::Array(parts) => parts.into_iter(),
+        _ => return events,
+    };
+    let name = match parts.next() {
+        // Take the name as a plain `String`; a name that isn't valid UTF-8 can't match any
+        // event, so the group is skipped.
+        Some(Value::String(s)) => match s.into_str() {
+            Some(name) => name,
+            None => return events,
+        },
+        _ => return events,
+    };
+    for call in parts {
+        let args = match call {
+            Value::Array(a) => a,
+            _ => continue,
+        };
+        match decode_ui_event(&name, args, client) {
+            Some(Ok(event)) => events.push(event),
+            // TODO: give the caller some way to find out about decode errors and unrecognized
+            // event names, instead of silently dropping them.
+            Some(Err(_)) | None => {}
+        }
+    }
+    events
+}
+
+impl NvimClient {
+    /// Returns a stream of the raw notifications (method name and arguments) sent by neovim,
+    /// e.g. the `redraw` notifications sent after `nvim_ui_attach`.
+    pub fn notifications(&self) -> impl Stream<Item = (String, Vec<Value>), Error = Error> {
+        self.client.notifications()
+            .map_err(|_e| Error::ConnectionClosed)
+    }
+
+    /// Returns a stream of decoded UI events. The caller must have already called
+    /// `nvim_ui_attach` so that neovim actually sends `redraw` notifications.
+    pub fn ui_events<'client>(&'client self) -> impl Stream<Item = UiEvent<'client>, Error = Error> + 'client {
+        let client = &self.client;
+        self.notifications()
+            .map(move |(name, args)| {
+                if name == "redraw" {
+                    redraw_batches(args).into_iter().flat_map(move |group| decode_redraw_group(group, client)).collect()
+                } else {
+                    Vec::new()
+                }
+            })
+            .map(stream::iter_ok)
+            .flatten()
+    }
+}
+
+// `redraw` is sent as a notification with a single argument: the array of `[event_name,
+// args...]` groups. This unwraps that one level of nesting; anything else isn't a `redraw`
+// notification we understand, so it yields no groups.
+fn redraw_batches(args: Vec<Value>) -> Vec<Value> {
+    match args.into_iter().next() {
+        Some(Value::Array(batches)) => batches,
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    // `FromValue`/`IntoValue` need a real `RpcClient` to hand around, even though the handle
+    // types below never actually use it.
+    fn test_client() -> RpcClient {
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let (a, _b) = UnixStream::pair(&handle).unwrap();
+        RpcClient::new(a, &handle)
+    }
+
+    #[test]
+    fn ext_handle_round_trips_through_into_value() {
+        nvim_type!(TestHandle, 99);
+
+        let client = test_client();
+        let wire = Value::Ext(99, rmp_serde::to_vec(&42i64).unwrap());
+
+        let decoded = TestHandle::from_value(wire.clone(), &client).unwrap();
+        assert_eq!(decoded.into_value(), wire);
+    }
+
+    #[test]
+    fn ext_handle_rejects_mismatched_id() {
+        nvim_type!(TestHandle, 99);
+
+        let client = test_client();
+        let wire = Value::Ext(7, rmp_serde::to_vec(&42i64).unwrap());
+
+        match TestHandle::from_value(wire, &client) {
+            Err(Error::UnexpectedReturnType(_)) => {}
+            other => panic!("expected UnexpectedReturnType, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nvim_error_array_is_decoded_into_structured_error() {
+        let wire = Value::Array(vec![Value::from(0i64), Value::from("boom")]);
+
+        match decode_nvim_error(wire) {
+            Error::NvimError(NvimError { kind: ErrorKind::Exception, ref message }) if message == "boom" => {}
+            other => panic!("expected a decoded NvimError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_error_array_falls_back_to_raw_value() {
+        let wire = Value::Array(vec![Value::from("not an id"), Value::from("boom")]);
+
+        match decode_nvim_error(wire.clone()) {
+            Error::NvimReturnedError(ref v) if *v == wire => {}
+            other => panic!("expected NvimReturnedError fallback, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn redraw_notification_unwraps_one_level() {
+        // A real `redraw` notification's args are `[ [group1...], [group2...] ]`: one argument
+        // that is itself the array of groups.
+        let groups = vec![
+            Value::Array(vec![Value::from("mode_info_set")]),
+            Value::Array(vec![Value::from("flush")]),
+        ];
+        let args = vec![Value::Array(groups.clone())];
+
+        assert_eq!(redraw_batches(args), groups);
+    }
+
+    #[test]
+    fn non_redraw_shaped_args_yield_no_batches() {
+        assert_eq!(redraw_batches(vec![]), Vec::new());
+        assert_eq!(redraw_batches(vec![Value::from("not an array")]), Vec::new());
+    }
+}
 